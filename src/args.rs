@@ -0,0 +1,104 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Which disk image layout to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareMode {
+    /// Flat BIOS image: bootloader bytes, then kernel info block, then the padded kernel.
+    Bios,
+    /// GPT-partitioned image with a FAT32 EFI System Partition.
+    Uefi,
+}
+
+/// Which top-level action to perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subcommand {
+    /// Build the disk image only.
+    Build,
+    /// Build the disk image, then boot it in QEMU.
+    Run,
+    /// Build and run every integration test under the kernel crate's `tests/` directory.
+    Test,
+}
+
+pub struct Args {
+    pub manifest_path: Option<PathBuf>,
+    pub target: Option<String>,
+    pub release: bool,
+    pub output: PathBuf,
+    pub firmware: FirmwareMode,
+    pub subcommand: Subcommand,
+    /// Extra arguments passed after `--`, forwarded to QEMU on `run`.
+    pub qemu_args: Vec<String>,
+    /// Optional initrd/ramdisk image to bundle alongside the kernel.
+    pub initrd: Option<PathBuf>,
+    /// Cross-compilation backend, either `"xargo"` or `"cargo"`; unparsed until merged
+    /// with the manifest default in `main`.
+    pub builder: Option<String>,
+    pub all_cargo: Vec<String>,
+}
+
+pub fn args() -> Args {
+    let mut manifest_path = None;
+    let mut target = None;
+    let mut release = false;
+    let mut output = PathBuf::from("bootimage.bin");
+    let mut firmware = FirmwareMode::Bios;
+    let mut subcommand = Subcommand::Build;
+    let mut qemu_args = Vec::new();
+    let mut initrd = None;
+    let mut builder = None;
+    let mut all_cargo = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "run" => subcommand = Subcommand::Run,
+            "test" => subcommand = Subcommand::Test,
+            "--" => qemu_args.extend(&mut args),
+            "--manifest-path" => {
+                let path = args.next().expect("--manifest-path expects a path argument");
+                manifest_path = Some(PathBuf::from(&path));
+                all_cargo.push(arg);
+                all_cargo.push(path);
+            }
+            "--target" => {
+                let value = args.next().expect("--target expects an argument");
+                target = Some(value.clone());
+                all_cargo.push(arg);
+                all_cargo.push(value);
+            }
+            "--release" => {
+                release = true;
+                all_cargo.push(arg);
+            }
+            "--output" => {
+                let value = args.next().expect("--output expects a path argument");
+                output = PathBuf::from(value);
+            }
+            "--uefi" => firmware = FirmwareMode::Uefi,
+            "--bios" => firmware = FirmwareMode::Bios,
+            "--initrd" => {
+                let path = args.next().expect("--initrd expects a path argument");
+                initrd = Some(PathBuf::from(path));
+            }
+            "--builder" => {
+                builder = Some(args.next().expect("--builder expects \"xargo\" or \"cargo\""));
+            }
+            other => all_cargo.push(other.to_owned()),
+        }
+    }
+
+    Args {
+        manifest_path,
+        target,
+        release,
+        output,
+        firmware,
+        subcommand,
+        qemu_args,
+        initrd,
+        builder,
+        all_cargo,
+    }
+}
@@ -0,0 +1,107 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Which cross-compilation backend builds the kernel and bootloader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builder {
+    /// The deprecated `xargo` wrapper around `cargo build`.
+    Xargo,
+    /// `cargo build -Z build-std` on a nightly toolchain that supports it.
+    Cargo,
+}
+
+impl Builder {
+    pub fn parse(value: &str) -> Builder {
+        match value {
+            "xargo" => Builder::Xargo,
+            "cargo" => Builder::Cargo,
+            other => panic!("`builder` must be \"xargo\" or \"cargo\", found: {:?}", other),
+        }
+    }
+}
+
+/// Builds the `Command` for a cross-compiling `build` through the selected backend.
+/// `pwd` doubles as the `RUST_TARGET_PATH` so custom target specs next to the manifest
+/// are picked up.
+fn build_command(builder: Builder, pwd: &Path, args: &[String], envs: &[(String, String)]) -> Command {
+    let mut command = match builder {
+        Builder::Xargo => {
+            let mut command = Command::new("xargo");
+            command.arg("build");
+            command
+        }
+        Builder::Cargo => {
+            let mut command = Command::new("cargo");
+            command.arg("build").arg("-Z").arg("build-std=core,compiler_builtins");
+            command
+        }
+    };
+
+    command.current_dir(pwd).env("RUST_TARGET_PATH", pwd);
+    command.args(args);
+    for &(ref key, ref value) in envs {
+        command.env(key, value);
+    }
+    command
+}
+
+/// Runs a cross-compiling `build` through the selected backend.
+pub fn run_build(builder: Builder, pwd: &Path, args: &[String], envs: &[(String, String)])
+    -> io::Result<std::process::ExitStatus>
+{
+    build_command(builder, pwd, args, envs).status()
+}
+
+/// Builds `test_name` with `--message-format=json` and returns the path to its compiled
+/// executable, read off the matching `compiler-artifact` message. Cargo/xargo never
+/// promote integration test binaries to an un-hashed path, so this is the only reliable
+/// way to find the binary `bootimage test` needs to boot.
+pub fn build_test_executable(builder: Builder, pwd: &Path, args: &[String], test_name: &str)
+    -> io::Result<PathBuf>
+{
+    let mut json_args = args.to_vec();
+    json_args.push(String::from("--message-format=json"));
+
+    let mut command = build_command(builder, pwd, &json_args, &[]);
+    command.stdout(Stdio::piped());
+    let mut child = command.spawn()?;
+
+    let mut stdout = String::new();
+    child.stdout.take().expect("piped stdout").read_to_string(&mut stdout)?;
+    if !child.wait()?.success() {
+        std::process::exit(1);
+    }
+
+    Ok(find_test_executable(&stdout, test_name).unwrap_or_else(|| {
+        panic!("no `compiler-artifact` message for test target {:?} in build output", test_name)
+    }))
+}
+
+/// Scans newline-delimited `--message-format=json` build output for the `compiler-artifact`
+/// message whose target is the `test_name` test binary, and returns its `executable` path.
+fn find_test_executable(build_output: &str, test_name: &str) -> Option<PathBuf> {
+    for line in build_output.lines() {
+        let message: serde_json::Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if message.get("reason").and_then(|reason| reason.as_str()) != Some("compiler-artifact") {
+            continue;
+        }
+
+        let target = &message["target"];
+        let is_test_target = target.get("kind")
+            .and_then(|kind| kind.as_array())
+            .map(|kinds| kinds.iter().any(|kind| kind.as_str() == Some("test")))
+            .unwrap_or(false);
+        let is_matching_name = target.get("name").and_then(|name| name.as_str()) == Some(test_name);
+        if !is_test_target || !is_matching_name {
+            continue;
+        }
+
+        return message.get("executable").and_then(|executable| executable.as_str())
+            .map(PathBuf::from);
+    }
+    None
+}
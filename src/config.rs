@@ -0,0 +1,142 @@
+use cargo_metadata::Package as CrateMetadata;
+
+/// Required alignment for addresses and sizes handed to the bootloader, in bytes.
+const ALIGNMENT: u64 = 0x1000;
+
+/// Bootloader settings read from the kernel crate's `[package.metadata.bootloader]` table.
+#[derive(Debug, Default, Clone)]
+pub struct BootloaderConfig {
+    pub physical_memory_offset: Option<u64>,
+    pub kernel_stack_address: Option<u64>,
+    pub kernel_stack_size: Option<u64>,
+}
+
+impl BootloaderConfig {
+    /// Turns the config into `KEY=value` environment variables for the bootloader's build script.
+    pub fn to_env(&self) -> Vec<(String, String)> {
+        let mut envs = Vec::new();
+        if let Some(offset) = self.physical_memory_offset {
+            envs.push(("PHYSICAL_MEMORY_OFFSET".into(), offset.to_string()));
+        }
+        if let Some(address) = self.kernel_stack_address {
+            envs.push(("KERNEL_STACK_ADDRESS".into(), address.to_string()));
+        }
+        if let Some(size) = self.kernel_stack_size {
+            envs.push(("KERNEL_STACK_SIZE".into(), size.to_string()));
+        }
+        envs
+    }
+}
+
+/// Reads the `[package.metadata.bootloader]` table from the kernel crate's manifest.
+pub fn read_bootloader_config(crate_: &CrateMetadata) -> BootloaderConfig {
+    let bootloader_table = match crate_.metadata.get("bootloader") {
+        Some(table) => table,
+        None => return BootloaderConfig::default(),
+    };
+
+    BootloaderConfig {
+        physical_memory_offset: parse_aligned(bootloader_table, "physical-memory-offset"),
+        kernel_stack_address: parse_aligned(bootloader_table, "kernel-stack-address"),
+        kernel_stack_size: parse_aligned(bootloader_table, "kernel-stack-size"),
+    }
+}
+
+/// Parses `key` out of `table` as an integer and asserts it is 4 KiB aligned.
+fn parse_aligned(table: &serde_json::Value, key: &str) -> Option<u64> {
+    let value = table.get(key)?;
+    let parsed = match *value {
+        serde_json::Value::Number(ref n) => n.as_u64()
+            .unwrap_or_else(|| panic!("`{}` must be an integer, found: {:?}", key, value)),
+        serde_json::Value::String(ref s) => parse_integer(s, key),
+        _ => panic!("`{}` must be an integer or a string, found: {:?}", key, value),
+    };
+
+    if parsed % ALIGNMENT != 0 {
+        panic!("`{}` must be 4 KiB aligned, found: {:#x}", key, parsed);
+    }
+
+    Some(parsed)
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal integer, panicking with `key` on failure.
+fn parse_integer(value: &str, key: &str) -> u64 {
+    let result = if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+
+    result.unwrap_or_else(|_| {
+        panic!("`{}` must be an integer (decimal or `0x`-prefixed hex), found: {:?}", key, value)
+    })
+}
+
+/// Settings for `bootimage run`, read from the kernel crate's `[package.metadata.bootimage]` table.
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    /// The QEMU binary and any fixed leading arguments, e.g. `["qemu-system-x86_64"]`.
+    pub run_command: Vec<String>,
+    /// Default QEMU flags, applied before any `-- <qemu args>` passed on the command line.
+    pub run_args: Vec<String>,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        RunConfig {
+            run_command: vec!["qemu-system-x86_64".into()],
+            run_args: Vec::new(),
+        }
+    }
+}
+
+/// Reads the `[package.metadata.bootimage]` table from the kernel crate's manifest.
+pub fn read_run_config(crate_: &CrateMetadata) -> RunConfig {
+    let bootimage_table = match crate_.metadata.get("bootimage") {
+        Some(table) => table,
+        None => return RunConfig::default(),
+    };
+
+    let run_command = bootimage_table.get("run-command")
+        .map(|value| vec![parse_string(value, "run-command")])
+        .unwrap_or_else(|| RunConfig::default().run_command);
+
+    let run_args = bootimage_table.get("run-args")
+        .map(|value| parse_string_array(value, "run-args"))
+        .unwrap_or_default();
+
+    RunConfig { run_command, run_args }
+}
+
+fn parse_string(value: &serde_json::Value, key: &str) -> String {
+    value.as_str().unwrap_or_else(|| panic!("`{}` must be a string, found: {:?}", key, value)).to_owned()
+}
+
+fn parse_string_array(value: &serde_json::Value, key: &str) -> Vec<String> {
+    value.as_array().unwrap_or_else(|| panic!("`{}` must be an array of strings, found: {:?}", key, value))
+        .iter()
+        .map(|entry| parse_string(entry, key))
+        .collect()
+}
+
+/// Disk image settings, read from the kernel crate's `[package.metadata.bootimage]` table.
+#[derive(Debug, Clone, Default)]
+pub struct ImageConfig {
+    /// Path to an initrd/ramdisk image to bundle alongside the kernel.
+    pub initrd: Option<String>,
+    /// Cross-compilation backend, `"xargo"` or `"cargo"`.
+    pub builder: Option<String>,
+}
+
+/// Reads the `[package.metadata.bootimage]` table from the kernel crate's manifest.
+pub fn read_image_config(crate_: &CrateMetadata) -> ImageConfig {
+    let bootimage_table = match crate_.metadata.get("bootimage") {
+        Some(table) => table,
+        None => return ImageConfig::default(),
+    };
+
+    ImageConfig {
+        initrd: bootimage_table.get("initrd").map(|value| parse_string(value, "initrd")),
+        builder: bootimage_table.get("builder").map(|value| parse_string(value, "builder")),
+    }
+}
@@ -1,18 +1,31 @@
 extern crate byteorder;
 extern crate xmas_elf;
-extern crate toml;
 extern crate cargo_metadata;
+extern crate gpt;
+extern crate fatfs;
+extern crate fscommon;
+extern crate blake3;
+extern crate wait_timeout;
+extern crate serde_json;
 
 use std::io;
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use byteorder::{ByteOrder, LittleEndian};
-use args::Args;
+use args::{Args, FirmwareMode, Subcommand};
 use cargo_metadata::Metadata as CargoMetadata;
 use cargo_metadata::Package as CrateMetadata;
 
 mod args;
+mod builder;
+mod config;
+mod test_runner;
+mod uefi;
+
+use builder::Builder;
+
+use config::BootloaderConfig;
 
 const BLOCK_SIZE: usize = 512;
 type KernelInfoBlock = [u8; BLOCK_SIZE];
@@ -46,16 +59,77 @@ fn run() -> Result<(), Error> {
     let args = args::args();
 
     let metadata = read_cargo_metadata(&args)?;
+    let crate_ = find_crate(&args, &metadata);
+    let bootloader_config = config::read_bootloader_config(crate_);
+    let image_config = config::read_image_config(crate_);
+    let builder = args.builder.clone().or_else(|| image_config.builder.clone())
+        .map(|value| Builder::parse(&value))
+        .unwrap_or(Builder::Xargo);
+
+    if args.subcommand == Subcommand::Test {
+        return run_tests(&args, &metadata, crate_, &bootloader_config, builder);
+    }
+
+    let (mut kernel, out_dir) = build_kernel(&args, &metadata, builder)?;
+
+    let mut kernel_bytes = Vec::new();
+    {
+        use std::io::Read;
+        kernel.read_to_end(&mut kernel_bytes)?;
+    }
+
+    let initrd_bytes = read_initrd(&args, &image_config)?;
+
+    let kernel_info_block = create_kernel_info_block(&kernel_bytes, initrd_bytes.as_deref());
+
+    let bootloader = build_bootloader(&out_dir, &bootloader_config, args.firmware, builder)?;
+
+    create_disk_image(&args.output, args.firmware, &kernel_bytes, initrd_bytes.as_deref(),
+        kernel_info_block, &bootloader)?;
+
+    if args.subcommand == Subcommand::Run {
+        let run_config = config::read_run_config(crate_);
+        let exit_status = run_in_qemu(&args.output, &run_config, &args.qemu_args)?;
+        if !exit_status.success() { std::process::exit(1) }
+    }
+
+    Ok(())
+}
+
+fn run_tests(args: &Args, metadata: &CargoMetadata, crate_: &CrateMetadata,
+    bootloader_config: &BootloaderConfig, builder: Builder) -> Result<(), Error>
+{
+    let crate_root = Path::new(&crate_.manifest_path).parent().unwrap();
+    let test_names = test_runner::discover_tests(crate_root)?;
+    if test_names.is_empty() {
+        println!("No integration tests found in {:?}", crate_root.join("tests"));
+        return Ok(());
+    }
 
-    let (kernel, out_dir) = build_kernel(&args, &metadata)?;
+    let run_config = config::read_run_config(crate_);
+    let mut outcomes = Vec::new();
+    for test_name in &test_names {
+        let (mut kernel, out_dir) = build_kernel_test(args, metadata, test_name, builder)?;
 
-    let kernel_size = kernel.metadata()?.len();
-    let kernel_info_block = create_kernel_info_block(kernel_size);
+        let mut kernel_bytes = Vec::new();
+        {
+            use std::io::Read;
+            kernel.read_to_end(&mut kernel_bytes)?;
+        }
+        let kernel_info_block = create_kernel_info_block(&kernel_bytes, None);
+
+        let bootloader = build_bootloader(&out_dir, bootloader_config, args.firmware, builder)?;
 
-    let bootloader = build_bootloader(&out_dir)?;
+        let mut image_path = out_dir.clone();
+        image_path.push(format!("bootimage-test-{}.bin", test_name));
+        create_disk_image(&image_path, args.firmware, &kernel_bytes, None, kernel_info_block, &bootloader)?;
 
-    create_disk_image(&args, kernel, kernel_info_block, &bootloader)?;
+        let outcome = test_runner::run_test(test_name, &image_path, &run_config.run_command)?;
+        outcomes.push(outcome);
+    }
 
+    let all_passed = test_runner::print_summary(&outcomes);
+    if !all_passed { std::process::exit(1) }
     Ok(())
 }
 
@@ -63,25 +137,49 @@ fn read_cargo_metadata(args: &Args) -> Result<CargoMetadata, cargo_metadata::Err
     cargo_metadata::metadata(args.manifest_path.as_ref().map(PathBuf::as_path))
 }
 
-fn build_kernel(args: &args::Args, metadata: &CargoMetadata) -> Result<(File, PathBuf), Error> {
+fn find_crate<'a>(args: &Args, metadata: &'a CargoMetadata) -> &'a CrateMetadata {
     let crate_root = PathBuf::from(&metadata.workspace_root);
     let manifest_path = args.manifest_path.as_ref().map(Clone::clone).unwrap_or({
         let mut path = crate_root.clone();
         path.push("Cargo.toml");
         path
     });
-    let crate_ = metadata.packages.iter().find(|p| Path::new(&p.manifest_path) == manifest_path)
-        .expect("Could not read crate name from cargo metadata");
-    let crate_name = &crate_.name;
+    metadata.packages.iter().find(|p| Path::new(&p.manifest_path) == manifest_path)
+        .expect("Could not read crate name from cargo metadata")
+}
 
-    let target_dir = PathBuf::from(&metadata.target_directory);
+fn build_kernel(args: &args::Args, metadata: &CargoMetadata, builder: Builder)
+    -> Result<(File, PathBuf), Error>
+{
+    let crate_name = find_crate(args, metadata).name.clone();
+    let out_dir = profile_out_dir(args, metadata);
 
-    // compile kernel
     println!("Building kernel");
-    let exit_status = run_xargo_build(&std::env::current_dir()?, &args.all_cargo)?;
+    let exit_status = builder::run_build(builder, &std::env::current_dir()?, &args.all_cargo, &[])?;
     if !exit_status.success() { std::process::exit(1) }
 
-    let mut out_dir = target_dir;
+    let kernel = File::open(out_dir.join(&crate_name))?;
+    Ok((kernel, out_dir))
+}
+
+fn build_kernel_test(args: &args::Args, metadata: &CargoMetadata, test_name: &str, builder: Builder)
+    -> Result<(File, PathBuf), Error>
+{
+    let out_dir = profile_out_dir(args, metadata);
+
+    println!("Building test {:?}", test_name);
+    let mut build_args = args.all_cargo.clone();
+    build_args.push(String::from("--test"));
+    build_args.push(test_name.to_owned());
+    let kernel_path = builder::build_test_executable(builder, &std::env::current_dir()?, &build_args, test_name)?;
+
+    let kernel = File::open(kernel_path)?;
+    Ok((kernel, out_dir))
+}
+
+/// The `target/<target>/{debug,release}` directory cargo/xargo builds into.
+fn profile_out_dir(args: &args::Args, metadata: &CargoMetadata) -> PathBuf {
+    let mut out_dir = PathBuf::from(&metadata.target_directory);
     if let &Some(ref target) = &args.target {
         out_dir.push(target);
     }
@@ -90,34 +188,52 @@ fn build_kernel(args: &args::Args, metadata: &CargoMetadata) -> Result<(File, Pa
     } else {
         out_dir.push("debug");
     }
-
-    let mut kernel_path = out_dir.clone();
-    kernel_path.push(crate_name);
-    let kernel = File::open(kernel_path)?;
-    Ok((kernel, out_dir))
+    out_dir
 }
 
-fn run_xargo_build(pwd: &Path, args: &[String]) -> io::Result<std::process::ExitStatus> {
-    let mut command = Command::new("xargo");
-    command.arg("build");
-    command.current_dir(pwd).env("RUST_TARGET_PATH", pwd);
-    command.args(args);
-    command.status()
-}
-
-fn create_kernel_info_block(kernel_size: u64) -> KernelInfoBlock {
-    let kernel_size = if kernel_size <= u64::from(u32::max_value()) {
-        kernel_size as u32
+// Layout of the 512-byte `KernelInfoBlock`:
+//   0..4    kernel size in bytes (u32, little-endian)
+//   4..36   BLAKE3 hash of the kernel image (32 bytes)
+//   36..40  initrd size in bytes (u32, little-endian), 0 if no initrd is present
+//   40..44  initrd offset from the start of the kernel data, in 512-byte blocks
+//   44..512 reserved, zero-filled
+fn create_kernel_info_block(kernel_bytes: &[u8], initrd_bytes: Option<&[u8]>) -> KernelInfoBlock {
+    let kernel_size = if kernel_bytes.len() as u64 <= u64::from(u32::max_value()) {
+        kernel_bytes.len() as u32
     } else {
         panic!("Kernel can't be loaded by BIOS bootloader because is too big")
     };
+    let kernel_hash = blake3::hash(kernel_bytes);
 
     let mut kernel_info_block = [0u8; BLOCK_SIZE];
     LittleEndian::write_u32(&mut kernel_info_block[0..4], kernel_size);
+    kernel_info_block[4..36].copy_from_slice(kernel_hash.as_bytes());
+
+    if let Some(initrd_bytes) = initrd_bytes {
+        let initrd_size = initrd_bytes.len() as u32;
+        let kernel_blocks = (kernel_size as u64 + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64;
+        LittleEndian::write_u32(&mut kernel_info_block[36..40], initrd_size);
+        LittleEndian::write_u32(&mut kernel_info_block[40..44], kernel_blocks as u32);
+    }
 
     kernel_info_block
 }
 
+/// Reads the initrd bytes, preferring `--initrd` over the `initrd` manifest key.
+fn read_initrd(args: &Args, image_config: &config::ImageConfig) -> io::Result<Option<Vec<u8>>> {
+    use std::io::Read;
+
+    let initrd_path = args.initrd.clone().or_else(|| image_config.initrd.as_ref().map(PathBuf::from));
+    let initrd_path = match initrd_path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let mut initrd_bytes = Vec::new();
+    File::open(initrd_path)?.read_to_end(&mut initrd_bytes)?;
+    Ok(Some(initrd_bytes))
+}
+
 fn download_bootloader(out_dir: &Path) -> Result<CrateMetadata, Error> {
     use std::io::Write;
 
@@ -166,15 +282,18 @@ fn download_bootloader(out_dir: &Path) -> Result<CrateMetadata, Error> {
     Ok(bootloader.clone())
 }
 
-fn build_bootloader(out_dir: &Path) -> Result<Box<[u8]>, Error> {
+fn build_bootloader(out_dir: &Path, bootloader_config: &BootloaderConfig, firmware: FirmwareMode,
+    builder: Builder) -> Result<Box<[u8]>, Error>
+{
     use std::io::Read;
 
     let bootloader_metadata = download_bootloader(out_dir)?;
     let bootloader_dir = Path::new(&bootloader_metadata.manifest_path).parent().unwrap();
 
-    let bootloader_target = "x86_64-bootloader";
-    let mut bootloader_path = bootloader_dir.to_path_buf();
-    bootloader_path.push("bootloader.bin");
+    let bootloader_target = match firmware {
+        FirmwareMode::Bios => "x86_64-bootloader",
+        FirmwareMode::Uefi => "x86_64-unknown-uefi",
+    };
 
     let args = &[
         String::from("--target"),
@@ -183,57 +302,90 @@ fn build_bootloader(out_dir: &Path) -> Result<Box<[u8]>, Error> {
     ];
 
     println!("Building bootloader");
-    let exit_status = run_xargo_build(bootloader_dir, args)?;
+    let exit_status = builder::run_build(builder, bootloader_dir, args, &bootloader_config.to_env())?;
     if !exit_status.success() { std::process::exit(1) }
 
-    let mut bootloader_elf_path = bootloader_dir.to_path_buf();
-    bootloader_elf_path.push("target");
-    bootloader_elf_path.push(bootloader_target);
-    bootloader_elf_path.push("release/bootloader");
+    let mut bootloader_bin_path = bootloader_dir.to_path_buf();
+    bootloader_bin_path.push("target");
+    bootloader_bin_path.push(bootloader_target);
+    bootloader_bin_path.push("release/bootloader");
+    if firmware == FirmwareMode::Uefi {
+        bootloader_bin_path.set_extension("efi");
+    }
 
-    let mut bootloader_elf_bytes = Vec::new();
-    let mut bootloader = File::open(&bootloader_elf_path).map_err(|err| {
-        Error::Bootloader(format!("Could not open bootloader at {:?}", bootloader_elf_path), err)
+    let mut bootloader_bytes = Vec::new();
+    let mut bootloader = File::open(&bootloader_bin_path).map_err(|err| {
+        Error::Bootloader(format!("Could not open bootloader at {:?}", bootloader_bin_path), err)
     })?;
-    bootloader.read_to_end(&mut bootloader_elf_bytes)?;
+    bootloader.read_to_end(&mut bootloader_bytes)?;
+
+    match firmware {
+        FirmwareMode::Bios => {
+            // extract the bootloader section of the ELF file; the rest is build scaffolding
+            let elf_file = xmas_elf::ElfFile::new(&bootloader_bytes).unwrap();
+            xmas_elf::header::sanity_check(&elf_file).unwrap();
+            let bootloader_section = elf_file.find_section_by_name(".bootloader")
+                .expect("bootloader must have a .bootloader section");
+            Ok(Vec::from(bootloader_section.raw_data(&elf_file)).into_boxed_slice())
+        }
+        FirmwareMode::Uefi => {
+            // the UEFI target already produces a ready-to-run PE executable
+            Ok(bootloader_bytes.into_boxed_slice())
+        }
+    }
+}
 
-    // copy bootloader section of ELF file to bootloader_path
-    let elf_file = xmas_elf::ElfFile::new(&bootloader_elf_bytes).unwrap();
-    xmas_elf::header::sanity_check(&elf_file).unwrap();
-    let bootloader_section = elf_file.find_section_by_name(".bootloader")
-        .expect("bootloader must have a .bootloader section");
+fn create_disk_image(output: &Path, firmware: FirmwareMode, kernel_bytes: &[u8],
+    initrd_bytes: Option<&[u8]>, kernel_info_block: KernelInfoBlock, bootloader_data: &[u8])
+    -> Result<(), Error>
+{
+    println!("Creating disk image at {:?}", output);
+    match firmware {
+        FirmwareMode::Bios => create_bios_disk_image(output, bootloader_data, &kernel_info_block,
+            kernel_bytes, initrd_bytes)?,
+        FirmwareMode::Uefi => uefi::create_uefi_image(output, bootloader_data, kernel_bytes,
+            initrd_bytes, &kernel_info_block).map_err(Error::Io)?,
+    }
 
-    Ok(Vec::from(bootloader_section.raw_data(&elf_file)).into_boxed_slice())
+    Ok(())
 }
 
-fn create_disk_image(args: &Args, mut kernel: File, kernel_info_block: KernelInfoBlock,
-    bootloader_data: &[u8]) -> Result<(), Error>
+fn create_bios_disk_image(output: &Path, bootloader_data: &[u8], kernel_info_block: &[u8],
+    kernel_bytes: &[u8], initrd_bytes: Option<&[u8]>) -> io::Result<()>
 {
-    use std::io::{Read, Write};
-
-    println!("Creating disk image at {:?}", args.output);
-    let mut output = File::create(&args.output)?;
-    output.write_all(&bootloader_data)?;
-    output.write_all(&kernel_info_block)?;
-
-    // write out kernel elf file
-    let kernel_size = kernel.metadata()?.len();
-    let mut buffer = [0u8; 1024];
-    loop {
-        let (n, interrupted) = match kernel.read(&mut buffer) {
-            Ok(0) => break,
-            Ok(n) => (n, false),
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (0, true),
-            Err(e) => Err(e)?,
-        };
-        if !interrupted {
-            output.write_all(&buffer[..n])?
-        }
-    }
+    use std::io::Write;
+
+    let mut output = File::create(output)?;
+    output.write_all(bootloader_data)?;
+    output.write_all(kernel_info_block)?;
+    output.write_all(kernel_bytes)?;
+    write_block_padding(&mut output, kernel_bytes.len())?;
 
-    let padding_size = ((512 - (kernel_size % 512)) % 512) as usize;
-    let padding = [0u8; 512];
-    output.write_all(&padding[..padding_size])?;
+    if let Some(initrd_bytes) = initrd_bytes {
+        output.write_all(initrd_bytes)?;
+        write_block_padding(&mut output, initrd_bytes.len())?;
+    }
 
     Ok(())
 }
+
+/// Pads `output` with zero bytes up to the next 512-byte block boundary after `written_len`.
+fn write_block_padding(output: &mut File, written_len: usize) -> io::Result<()> {
+    use std::io::Write;
+
+    let padding_size = ((512 - (written_len % 512)) % 512) as usize;
+    output.write_all(&[0u8; 512][..padding_size])
+}
+
+fn run_in_qemu(output: &Path, run_config: &config::RunConfig, extra_args: &[String])
+    -> io::Result<std::process::ExitStatus>
+{
+    let mut command = Command::new(&run_config.run_command[0]);
+    command.args(&run_config.run_command[1..]);
+    command.args(&run_config.run_args);
+    command.arg("-drive").arg(format!("format=raw,file={}", output.display()));
+    command.args(extra_args);
+
+    println!("Running {:?} in QEMU", output);
+    command.status()
+}
@@ -0,0 +1,84 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use wait_timeout::ChildExt;
+
+/// QEMU's isa-debug-exit device, wired to port 0xf4.
+const ISA_DEBUG_EXIT_DEVICE: &str = "isa-debug-exit,iobase=0xf4,iosize=0x04";
+/// Exit code a test kernel writes to port 0xf4 to report success; QEMU then exits
+/// with status `(code << 1) | 1`.
+const SUCCESS_EXIT_CODE: u32 = 0x10;
+/// How long a single test is allowed to run before it's considered hung.
+const TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct TestOutcome {
+    pub name: String,
+    pub passed: bool,
+}
+
+/// Finds the integration test source files under `<kernel_root>/tests/*.rs`.
+pub fn discover_tests(kernel_root: &Path) -> io::Result<Vec<String>> {
+    let tests_dir = kernel_root.join("tests");
+    if !tests_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut tests = Vec::new();
+    for entry in fs::read_dir(tests_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some("rs") {
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                tests.push(stem.to_owned());
+            }
+        }
+    }
+    tests.sort();
+    Ok(tests)
+}
+
+/// Boots `image` in QEMU with isa-debug-exit enabled and classifies the result.
+pub fn run_test(name: &str, image: &Path, run_command: &[String]) -> io::Result<TestOutcome> {
+    println!("Running test {:?}", name);
+
+    let mut command = Command::new(&run_command[0]);
+    command.args(&run_command[1..]);
+    command.arg("-device").arg(ISA_DEBUG_EXIT_DEVICE);
+    command.arg("-drive").arg(format!("format=raw,file={}", image.display()));
+    command.arg("-display").arg("none");
+    command.arg("-serial").arg("stdio");
+    command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+
+    let mut child = command.spawn()?;
+    let passed = match child.wait_timeout(TEST_TIMEOUT)? {
+        Some(status) => status.code()
+            .map(|code| code as u32 == (SUCCESS_EXIT_CODE << 1) | 1)
+            .unwrap_or(false),
+        None => {
+            println!("Test {:?} timed out after {:?}", name, TEST_TIMEOUT);
+            child.kill()?;
+            child.wait()?;
+            false
+        }
+    };
+
+    Ok(TestOutcome { name: name.to_owned(), passed })
+}
+
+/// Prints a per-test pass/fail summary and reports whether every test passed.
+pub fn print_summary(outcomes: &[TestOutcome]) -> bool {
+    println!();
+    println!("Test summary:");
+    let mut all_passed = true;
+    for outcome in outcomes {
+        if outcome.passed {
+            println!("  {} ... ok", outcome.name);
+        } else {
+            println!("  {} ... FAILED", outcome.name);
+            all_passed = false;
+        }
+    }
+    all_passed
+}
@@ -0,0 +1,79 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use gpt::{mbr, GptConfig, partition_types};
+
+/// Size of the EFI System Partition, in bytes.
+const ESP_SIZE: u64 = 64 * 1024 * 1024;
+/// Where the ESP starts, leaving room for the protective MBR and the GPT header/table.
+const ESP_OFFSET: u64 = 1024 * 1024;
+
+/// Panics with a named, actionable message if `payload` is too large to fit in the ESP,
+/// instead of letting it fail partway through with an opaque `fatfs` IO error.
+fn check_fits(payload: &str, size: u64) {
+    if size > ESP_SIZE {
+        panic!("{} is {} bytes, which does not fit in the {} byte EFI System Partition", payload, size, ESP_SIZE);
+    }
+}
+
+/// Builds a GPT-partitioned disk image with a FAT32 EFI System Partition holding the
+/// bootloader's EFI executable at `EFI/BOOT/BOOTX64.EFI`, plus the kernel, its info
+/// block, and an optional initrd as regular files.
+pub fn create_uefi_image(
+    output: &Path,
+    bootloader_efi: &[u8],
+    kernel_bytes: &[u8],
+    initrd_bytes: Option<&[u8]>,
+    kernel_info_block: &[u8],
+) -> io::Result<()> {
+    check_fits("bootloader", bootloader_efi.len() as u64);
+    check_fits("kernel", kernel_bytes.len() as u64);
+    check_fits("kernel info block", kernel_info_block.len() as u64);
+    if let Some(initrd_bytes) = initrd_bytes {
+        check_fits("initrd", initrd_bytes.len() as u64);
+    }
+
+    let image_size = ESP_OFFSET + ESP_SIZE;
+    {
+        let image = File::create(output)?;
+        image.set_len(image_size)?;
+    }
+
+    mbr::ProtectiveMBR::with_lb_size((image_size / 512 - 1) as u32)
+        .overwrite_lba0(&mut OpenOptions::new().write(true).open(output)?)
+        .expect("failed to write protective MBR");
+
+    let mut gpt_disk = GptConfig::new()
+        .writable(true)
+        .open(output)
+        .expect("failed to open GPT disk");
+    gpt_disk
+        .update_partitions(Default::default())
+        .expect("failed to initialize GPT partition table");
+    // Without an explicit alignment, `gpt` packs the partition right after the header/table
+    // region (LBA 34), not at ESP_OFFSET, leaving the FAT volume we format below orphaned
+    // outside the partition the GPT actually points firmware at.
+    gpt_disk
+        .add_partition("EFI System Partition", ESP_SIZE, partition_types::EFI, 0, Some(ESP_OFFSET / 512))
+        .expect("failed to add EFI System Partition");
+    gpt_disk.write().expect("failed to write GPT");
+
+    let esp = OpenOptions::new().read(true).write(true).open(output)?;
+    let mut esp = fscommon::StreamSlice::new(esp, ESP_OFFSET, ESP_OFFSET + ESP_SIZE)?;
+    fatfs::format_volume(&mut esp, FormatVolumeOptions::new().fat_type(FatType::Fat32))?;
+
+    let fs = FileSystem::new(esp, FsOptions::new())?;
+    let root = fs.root_dir();
+    let efi_dir = root.create_dir("EFI")?;
+    let boot_dir = efi_dir.create_dir("BOOT")?;
+    boot_dir.create_file("BOOTX64.EFI")?.write_all(bootloader_efi)?;
+    root.create_file("kernel.elf")?.write_all(kernel_bytes)?;
+    root.create_file("kernel.info")?.write_all(kernel_info_block)?;
+    if let Some(initrd_bytes) = initrd_bytes {
+        root.create_file("initrd")?.write_all(initrd_bytes)?;
+    }
+
+    Ok(())
+}